@@ -0,0 +1,637 @@
+use std::{
+	collections::HashSet,
+	net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+	time::{Duration, Instant},
+};
+
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::yak::{Signature, U1024, Yak};
+
+/// The number of entries kept per k-bucket.
+const K: usize = 16;
+
+/// The number of peers queried concurrently during a lookup.
+const ALPHA: usize = 3;
+
+/// The number of bits in a [`NodeId`], and therefore the number of buckets.
+const ID_BITS: usize = 256;
+
+/// How long a known node may go without being heard from before it is pinged
+/// and, if still silent, evicted.
+const STALE: Duration = Duration::from_secs(30);
+
+/// How often the routing table is refreshed with a self lookup.
+const REFRESH: Duration = Duration::from_secs(15);
+
+/// The maximum number of rounds a single lookup will run before converging.
+const MAX_STEPS: usize = 8;
+
+/// The size of a serialized long term public key on the wire.
+const PUBLIC_KEY_SIZE: usize = 128;
+
+/// A 256 bit node identity derived from a node's long term public key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId([u8; 32]);
+
+impl NodeId {
+	/// Derives a node identity by hashing the long term public key.
+	pub fn from_public_key(key: U1024) -> Self {
+		let mut bytes = [0; 128];
+
+		key.to_little_endian(&mut bytes);
+
+		let mut hasher = Keccak::v256();
+
+		hasher.update(&bytes);
+
+		let mut id = [0; 32];
+
+		hasher.finalize(&mut id);
+
+		Self(id)
+	}
+
+	/// Returns the XOR distance between two identities.
+	fn distance(&self, other: &Self) -> [u8; 32] {
+		let mut out = [0; 32];
+
+		for (index, byte) in out.iter_mut().enumerate() {
+			*byte = self.0[index] ^ other.0[index];
+		}
+
+		out
+	}
+
+	/// Returns the bucket index for `other`, i.e. the position of the most
+	/// significant differing bit. Returns `None` when the identities are equal.
+	fn bucket(&self, other: &Self) -> Option<usize> {
+		let distance = self.distance(other);
+
+		for (index, byte) in distance.iter().enumerate() {
+			if *byte != 0 {
+				let within = byte.leading_zeros() as usize;
+
+				return Some(ID_BITS - 1 - (index * 8 + within));
+			}
+		}
+
+		None
+	}
+}
+
+/// A routable node: its identity, the UDP address discovery talks to, and the
+/// TCP address a [`crate::session::Session`] connects to.
+#[derive(Clone)]
+pub struct Node {
+	pub id: NodeId,
+	pub udp: SocketAddr,
+	pub tcp: SocketAddr,
+
+	/// When this node was last heard from.
+	seen: Instant,
+
+	/// When this node was last pinged for having gone quiet, so `sweep`
+	/// only re-pings once per `STALE` interval instead of every call.
+	last_pinged: Option<Instant>,
+}
+
+/// The Kademlia routing table: `ID_BITS` k-buckets indexed by the position of
+/// the most significant bit by which a node differs from the local identity.
+struct RoutingTable {
+	local: NodeId,
+	buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+	/// Creates an empty routing table for the given local identity.
+	fn new(local: NodeId) -> Self {
+		Self {
+			local,
+			buckets: (0..ID_BITS).map(|_| Vec::new()).collect(),
+		}
+	}
+
+	/// Records a sighting of `node`, refreshing an existing entry or inserting a
+	/// new one while the bucket has room.
+	fn touch(&mut self, node: Node) {
+		let Some(index) = self.local.bucket(&node.id) else {
+			return;
+		};
+
+		let bucket = &mut self.buckets[index];
+
+		if let Some(existing) = bucket.iter_mut().find(|entry| entry.id == node.id) {
+			existing.udp = node.udp;
+			existing.tcp = node.tcp;
+			existing.seen = node.seen;
+			existing.last_pinged = None;
+		} else if bucket.len() < K {
+			bucket.push(node);
+		}
+	}
+
+	/// Returns up to `count` known nodes closest to `target` by XOR distance.
+	fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+		let mut nodes: Vec<Node> = self.buckets.iter().flatten().cloned().collect();
+
+		nodes.sort_by_key(|node| target.distance(&node.id));
+		nodes.truncate(count);
+
+		nodes
+	}
+
+	/// Drops nodes that have not been heard from within twice `STALE`, returning
+	/// the UDP addresses of nodes silent for longer than `STALE` that should be
+	/// pinged before a later sweep evicts them.
+	/// Each node is only added to that list once per `STALE` interval, rather
+	/// than on every call, so a quiet node isn't re-pinged every frame.
+	fn sweep(&mut self, now: Instant) -> Vec<SocketAddr> {
+		let mut stale = Vec::new();
+
+		for bucket in &mut self.buckets {
+			bucket.retain_mut(|node| {
+				let expired = now.duration_since(node.seen) > STALE.saturating_mul(2);
+
+				if !expired && now.duration_since(node.seen) > STALE {
+					let due = node.last_pinged.is_none_or(|at| now.duration_since(at) > STALE);
+
+					if due {
+						node.last_pinged = Some(now);
+						stale.push(node.udp);
+					}
+				}
+
+				!expired
+			});
+		}
+
+		stale
+	}
+}
+
+/// A message exchanged over the discovery UDP socket.
+enum Message {
+	/// A liveness probe.
+	Ping,
+
+	/// A reply to a [`Message::Ping`].
+	Pong,
+
+	/// A request for the `K` nodes closest to a target identity.
+	FindNode { target: NodeId },
+
+	/// A reply to a [`Message::FindNode`] carrying known close nodes.
+	Nodes { nodes: Vec<Node> },
+}
+
+/// Appends a (v4) socket address to the buffer.
+fn write_addr(buffer: &mut Vec<u8>, addr: SocketAddr) {
+	let addr = match addr {
+		SocketAddr::V4(addr) => addr,
+		SocketAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+	};
+
+	buffer.extend_from_slice(&addr.ip().octets());
+	buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Reads a (v4) socket address from the cursor, advancing it.
+fn read_addr(data: &[u8], offset: &mut usize) -> Option<SocketAddr> {
+	let end = *offset + 6;
+	let bytes = data.get(*offset..end)?;
+
+	*offset = end;
+
+	let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+	let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+
+	Some(SocketAddr::from(SocketAddrV4::new(ip, port)))
+}
+
+impl Message {
+	/// Encodes the message as its discriminant, the sender's identity and
+	/// addresses, the discriminant-specific payload, the sender's long term
+	/// public key, and a signature over everything that precedes the key — so the
+	/// receiver can authenticate who it is actually hearing from, and that
+	/// this exact message (not just its claimed identity) is what they sent.
+	fn encode(&self, local: &Node, identity: &mut Yak) -> Vec<u8> {
+		let mut buffer = Vec::new();
+
+		let discriminant = match self {
+			Self::Ping => 0,
+			Self::Pong => 1,
+			Self::FindNode { .. } => 2,
+			Self::Nodes { .. } => 3,
+		};
+
+		buffer.push(discriminant);
+		buffer.extend_from_slice(&local.id.0);
+		write_addr(&mut buffer, local.udp);
+		write_addr(&mut buffer, local.tcp);
+
+		match self {
+			Self::Ping | Self::Pong => {}
+			Self::FindNode { target } => buffer.extend_from_slice(&target.0),
+			Self::Nodes { nodes } => {
+				buffer.extend_from_slice(&(nodes.len() as u16).to_be_bytes());
+
+				for node in nodes {
+					buffer.extend_from_slice(&node.id.0);
+					write_addr(&mut buffer, node.udp);
+					write_addr(&mut buffer, node.tcp);
+				}
+			}
+		}
+
+		let signature = identity.sign(&buffer);
+		let mut public_key_bytes = [0; PUBLIC_KEY_SIZE];
+
+		identity.public_key().to_little_endian(&mut public_key_bytes);
+
+		buffer.extend_from_slice(&public_key_bytes);
+		buffer.extend_from_slice(&signature.to_bytes());
+
+		buffer
+	}
+
+	/// Decodes a message along with the sender [`Node`] it advertised,
+	/// rejecting it unless its signature proves the sender actually holds the
+	/// private key behind the claimed identity and actually sent this exact
+	/// message.
+	fn decode(data: &[u8], now: Instant) -> Option<(Node, Self)> {
+		let discriminant = *data.first()?;
+		let mut offset = 1;
+
+		let id = NodeId(data.get(offset..offset + 32)?.try_into().ok()?);
+
+		offset += 32;
+
+		let udp = read_addr(data, &mut offset)?;
+		let tcp = read_addr(data, &mut offset)?;
+
+		let message = match discriminant {
+			0 => Self::Ping,
+			1 => Self::Pong,
+			2 => {
+				let target = NodeId(data.get(offset..offset + 32)?.try_into().ok()?);
+
+				offset += 32;
+
+				Self::FindNode { target }
+			}
+			3 => {
+				let count = u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?);
+
+				offset += 2;
+
+				let mut nodes = Vec::new();
+
+				for _ in 0..count {
+					let id = NodeId(data.get(offset..offset + 32)?.try_into().ok()?);
+
+					offset += 32;
+
+					let udp = read_addr(data, &mut offset)?;
+					let tcp = read_addr(data, &mut offset)?;
+
+					nodes.push(Node {
+						id,
+						udp,
+						tcp,
+						seen: now,
+						last_pinged: None,
+					});
+				}
+
+				Self::Nodes { nodes }
+			}
+			_ => return None,
+		};
+
+		let signed_end = offset;
+
+		let public_key_bytes: [u8; PUBLIC_KEY_SIZE] =
+			data.get(offset..offset + PUBLIC_KEY_SIZE)?.try_into().ok()?;
+
+		offset += PUBLIC_KEY_SIZE;
+
+		let public_key = U1024::from_little_endian(&public_key_bytes);
+
+		if NodeId::from_public_key(public_key) != id {
+			return None;
+		}
+
+		let signature_bytes: [u8; Signature::SIZE] =
+			data.get(offset..offset + Signature::SIZE)?.try_into().ok()?;
+
+		let signature = Signature::from_bytes(&signature_bytes);
+
+		if !signature.verify(&data[..signed_end], public_key) {
+			return None;
+		}
+
+		let sender = Node {
+			id,
+			udp,
+			tcp,
+			seen: now,
+			last_pinged: None,
+		};
+
+		Some((sender, message))
+	}
+}
+
+/// An in-progress iterative `FIND_NODE` lookup that converges over a bounded
+/// number of rounds towards the nodes closest to `target`.
+struct Lookup {
+	target: NodeId,
+	shortlist: Vec<Node>,
+	queried: HashSet<NodeId>,
+	steps: usize,
+}
+
+impl Lookup {
+	/// Merges freshly learned nodes into the shortlist, keeping it sorted by
+	/// distance to the target and bounded to `K` entries.
+	fn merge(&mut self, nodes: Vec<Node>) {
+		for node in nodes {
+			if !self.shortlist.iter().any(|entry| entry.id == node.id) {
+				self.shortlist.push(node);
+			}
+		}
+
+		let target = self.target;
+
+		self.shortlist.sort_by_key(|node| target.distance(&node.id));
+		self.shortlist.truncate(K);
+	}
+
+	/// Returns the next batch of up to `ALPHA` closest nodes that have not yet
+	/// been queried, marking them as queried.
+	fn next_batch(&mut self) -> Vec<Node> {
+		let batch: Vec<Node> = self
+			.shortlist
+			.iter()
+			.filter(|node| !self.queried.contains(&node.id))
+			.take(ALPHA)
+			.cloned()
+			.collect();
+
+		for node in &batch {
+			self.queried.insert(node.id);
+		}
+
+		self.steps += 1;
+
+		batch
+	}
+
+	/// Returns `true` once the lookup has converged or exhausted its rounds.
+	fn finished(&self) -> bool {
+		self.steps >= MAX_STEPS
+			|| self
+				.shortlist
+				.iter()
+				.all(|node| self.queried.contains(&node.id))
+	}
+}
+
+/// The discovery subsystem: a UDP socket, a Kademlia routing table, and an
+/// optional active lookup, all polled from the application's event loop.
+pub struct Discovery {
+	socket: UdpSocket,
+
+	/// The long term identity key used to sign every outgoing packet, kept
+	/// for the lifetime of the node so its signatures stay consistent with
+	/// the public key its [`NodeId`] was derived from.
+	identity: Yak,
+
+	local: Node,
+	table: RoutingTable,
+	lookup: Option<Lookup>,
+	refreshed: Instant,
+}
+
+impl Discovery {
+	/// Creates a discovery node bound to `socket`, identified by `identity`'s
+	/// long term public key, and advertising `tcp` as its session address.
+	pub fn new(socket: UdpSocket, identity: Yak, tcp: SocketAddr) -> std::io::Result<Self> {
+		socket.set_nonblocking(true)?;
+
+		let id = NodeId::from_public_key(identity.public_key());
+		let udp = socket.local_addr()?;
+		let now = Instant::now();
+
+		Ok(Self {
+			socket,
+			identity,
+			local: Node {
+				id,
+				udp,
+				tcp,
+				seen: now,
+				last_pinged: None,
+			},
+			table: RoutingTable::new(id),
+			lookup: None,
+			refreshed: now,
+		})
+	}
+
+	/// Sends a message to the given UDP address, signed with the local
+	/// identity key.
+	fn send(&mut self, message: &Message, to: SocketAddr) {
+		let encoded = message.encode(&self.local, &mut self.identity);
+
+		let _ = self.socket.send_to(&encoded, to);
+	}
+
+	/// Joins the network through a known node's UDP address by pinging it and
+	/// asking it for the nodes closest to the local identity. No lookup is
+	/// started here: the known node enters the routing table once it answers,
+	/// and the nodes listed in its reply are only used if a lookup is active.
+	pub fn bootstrap(&mut self, addr: SocketAddr) {
+		self.send(&Message::Ping, addr);
+		self.send(&Message::FindNode { target: self.local.id }, addr);
+	}
+
+	/// Reads and handles every datagram currently waiting on the socket.
+	pub fn poll(&mut self) {
+		let mut datagram = [0; 1 << 12];
+
+		while let Ok((len, from)) = self.socket.recv_from(&mut datagram) {
+			let now = Instant::now();
+
+			let Some((mut sender, message)) = Message::decode(&datagram[..len], now) else {
+				continue;
+			};
+
+			// Trust the observed source for the UDP address so a peer behind a
+			// remapped port is still reachable.
+			sender.udp = from;
+
+			self.table.touch(sender.clone());
+
+			match message {
+				Message::Ping => self.send(&Message::Pong, from),
+				Message::Pong => {}
+				Message::FindNode { target } => {
+					let nodes = self.table.closest(&target, K);
+
+					self.send(&Message::Nodes { nodes }, from);
+				}
+				Message::Nodes { nodes } => {
+					// These entries are only the sender's unsigned claims about
+					// other nodes, not proof those nodes hold the identity
+					// advertised for them, so they are queried directly rather
+					// than trusted into the routing table here. `touch` only
+					// runs once above, against a packet whose own signature was
+					// just verified.
+					if let Some(lookup) = &mut self.lookup {
+						lookup.merge(nodes);
+					}
+				}
+			}
+		}
+	}
+
+	/// Advances periodic maintenance: evicts stale nodes, refreshes the table
+	/// with a self lookup, and drives any active lookup one round forward.
+	pub fn tick(&mut self) {
+		let now = Instant::now();
+
+		for addr in self.table.sweep(now) {
+			self.send(&Message::Ping, addr);
+		}
+
+		if now.duration_since(self.refreshed) > REFRESH {
+			self.refreshed = now;
+
+			self.start_lookup(self.local.id);
+		}
+
+		self.drive_lookup();
+	}
+
+	/// Begins an iterative lookup for `target`, seeded from the routing table.
+	fn start_lookup(&mut self, target: NodeId) {
+		let mut lookup = Lookup {
+			target,
+			shortlist: self.table.closest(&target, K),
+			queried: HashSet::new(),
+			steps: 0,
+		};
+
+		for node in lookup.next_batch() {
+			self.send(&Message::FindNode { target }, node.udp);
+		}
+
+		self.lookup = Some(lookup);
+	}
+
+	/// Sends the next round of queries for the active lookup, clearing it once
+	/// it has converged.
+	fn drive_lookup(&mut self) {
+		let Some(lookup) = &mut self.lookup else {
+			return;
+		};
+
+		if lookup.finished() {
+			self.lookup = None;
+
+			return;
+		}
+
+		let target = lookup.target;
+
+		for node in lookup.next_batch() {
+			self.send(&Message::FindNode { target }, node.udp);
+		}
+	}
+
+	/// Returns the discovered peers as `(id, tcp address)` pairs so the UI can
+	/// offer them as connection targets.
+	pub fn discovered(&self) -> Vec<(NodeId, SocketAddr)> {
+		self.table
+			.buckets
+			.iter()
+			.flatten()
+			.map(|node| (node.id, node.tcp))
+			.collect()
+	}
+}
+
+impl NodeId {
+	/// Returns a short hexadecimal prefix of the identity for display.
+	pub fn short(&self) -> String {
+		self.0[..4].iter().map(|byte| format!("{byte:02x}")).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Builds a local [`Node`] advertising `identity`'s public key.
+	fn local_node(identity: &Yak) -> Node {
+		Node {
+			id: NodeId::from_public_key(identity.public_key()),
+			udp: SocketAddr::from(([127, 0, 0, 1], 30303)),
+			tcp: SocketAddr::from(([127, 0, 0, 1], 30304)),
+			seen: Instant::now(),
+			last_pinged: None,
+		}
+	}
+
+	#[test]
+	fn signed_message_round_trips() {
+		let mut identity = Yak::new();
+		let local = local_node(&identity);
+		let encoded = Message::Ping.encode(&local, &mut identity);
+
+		let (sender, message) = Message::decode(&encoded, Instant::now()).unwrap();
+
+		assert!(sender.id == local.id);
+		assert!(matches!(message, Message::Ping));
+	}
+
+	#[test]
+	fn tampered_discriminant_is_rejected() {
+		let mut identity = Yak::new();
+		let local = local_node(&identity);
+		let mut encoded = Message::Ping.encode(&local, &mut identity);
+
+		// Try to pass this signed Ping off as a FindNode.
+		encoded[0] = 2;
+
+		assert!(Message::decode(&encoded, Instant::now()).is_none());
+	}
+
+	#[test]
+	fn tampered_payload_is_rejected() {
+		let mut identity = Yak::new();
+		let local = local_node(&identity);
+		let target = NodeId::from_public_key(Yak::new().public_key());
+		let mut encoded = Message::FindNode { target }.encode(&local, &mut identity);
+
+		let payload_start = 1 + 32 + 6 + 6; // discriminant + id + udp + tcp
+
+		encoded[payload_start] ^= 0x01;
+
+		assert!(Message::decode(&encoded, Instant::now()).is_none());
+	}
+
+	#[test]
+	fn claimed_id_must_match_public_key() {
+		let mut identity = Yak::new();
+		let mut local = local_node(&identity);
+
+		local.id = NodeId::from_public_key(Yak::new().public_key());
+
+		let encoded = Message::Ping.encode(&local, &mut identity);
+
+		assert!(Message::decode(&encoded, Instant::now()).is_none());
+	}
+}
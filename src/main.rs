@@ -1,71 +1,77 @@
 use std::{
+	collections::HashMap,
 	fmt::{Arguments, Write},
-	net::{SocketAddr, TcpListener},
+	net::{SocketAddr, TcpListener, UdpSocket},
 };
 
 use eframe::{
-	egui::{Button, CentralPanel, Context, Grid, TextEdit, Vec2},
+	egui::{Button, CentralPanel, Context, TextEdit, Vec2},
 	epaint::Color32,
 	Frame, NativeOptions,
 };
 
 use self::{
+	discovery::Discovery,
 	session::{Packet, Session},
 	yak::Yak,
 };
 
+mod discovery;
 mod rc4;
 mod session;
 mod yak;
 
-/// The status shown to the user in the UI.
-#[derive(Clone, Copy)]
-enum Status {
-	Active,
-	Inactive,
-}
+/// A stable identifier handed to each connection, used to index the session
+/// table so peers can be added and removed without disturbing the others.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Token(usize);
 
-impl Status {
-	/// Returns `true` if the status is [`Status::Active`].
-	const fn is_active(self) -> bool {
-		matches!(self, Self::Active)
-	}
+/// A single connected peer.
+///
+/// Each peer owns its own [`Yak`] handshake state, its own [`Session`] (and
+/// therefore its own pair of RC4 ciphers), and its own message log.
+struct Peer {
+	/// The handshake state used to derive this peer's shared secret.
+	yak: Yak,
 
-	/// Returns `true` if the status is [`Status::Inactive`].
-	const fn is_inactive(self) -> bool {
-		matches!(self, Self::Inactive)
-	}
+	/// The transport session for this peer.
+	session: Session,
 
-	/// Returns the color and label for the status.
-	const fn as_label(self) -> (Color32, &'static str) {
-		match self {
-			Self::Active => (Color32::LIGHT_GREEN, "Active"),
-			Self::Inactive => (Color32::LIGHT_RED, "Inactive"),
-		}
-	}
+	/// A human-readable label, typically the peer's address.
+	label: String,
 
-	/// Returns the label for the button to toggle the connection.
-	const fn as_set_reset(self) -> &'static str {
-		match self {
-			Self::Active => "Reset",
-			Self::Inactive => "Set",
-		}
-	}
+	/// The per-peer message log shown when this peer is selected.
+	log: String,
+
+	/// Whether the key exchange with this peer has completed.
+	secure: bool,
+
+	/// Whether this side opened the TCP connection (`true`) or accepted it
+	/// (`false`). Passed to [`Session::secure`] so the two peers' MAC chains
+	/// get distinct domain-separation labels.
+	initiator: bool,
 }
 
 /// The main application.
 ///
-/// It consists of the [`Yak`] instance, the [`TcpListener`] for incoming connections, the current
-/// [`Session`], the recipient address, the message box, and the output label.
+/// It consists of the [`TcpListener`] for incoming connections, a table of
+/// active [`Peer`]s indexed by [`Token`], the currently selected peer, the
+/// recipient address, the message box, and the network log.
 struct Application {
-	/// The [`Yak`] instance used to compute the keys.
-	yak: Yak,
+	/// The Kademlia discovery subsystem.
+	discovery: Discovery,
 
 	/// The [`TcpListener`] for incoming connections.
 	server: TcpListener,
 
-	/// The current [`Session`] for transmitting data.
-	session: Option<Session>,
+	/// The active peers, each with its own handshake and ciphers.
+	peers: HashMap<Token, Peer>,
+
+	/// The next [`Token`] to hand out.
+	next_token: usize,
+
+	/// The peer the message box currently targets.
+	selected: Option<Token>,
 
 	/// Various information and state for the UI.
 	recipient: String,
@@ -76,18 +82,20 @@ struct Application {
 impl Application {
 	/// Creates a new [`Application`] instance with the given [`TcpListener`]
 	/// already bound to the port.
-	fn new(server: TcpListener) -> Self {
+	fn new(server: TcpListener, discovery: Discovery) -> Self {
 		Self {
-			yak: Yak::new(),
+			discovery,
 			server,
-			session: None,
+			peers: HashMap::new(),
+			next_token: 0,
+			selected: None,
 			recipient: String::new(),
 			message_box: String::new(),
 			output_label: String::new(),
 		}
 	}
 
-	/// Logs the given arguments to the output label. This is
+	/// Logs the given arguments to the network log. This is
 	/// shown to the user in the UI.
 	fn log(&mut self, name: &str, arguments: Arguments) {
 		let mut buffer = String::new();
@@ -97,30 +105,49 @@ impl Application {
 		self.output_label = buffer + &self.output_label;
 	}
 
-	/// Sets the current [`Session`] to the given one.
-	/// This will also send an [`Packet::Acknowledge`] packet to the
-	/// recipient.
-	fn set_session(&mut self, mut session: Session) {
-		let key = self.yak.start_session().into();
+	/// Registers a [`Session`] as a new peer, kicking off its key exchange with
+	/// an [`Packet::Acknowledge`] packet and returning its [`Token`].
+	///
+	/// `initiator` must be `true` when this side opened the TCP connection and
+	/// `false` when it accepted one, so the MAC chains can be given distinct
+	/// labels per direction once the key exchange completes.
+	fn add_peer(&mut self, mut session: Session, label: String, initiator: bool) -> Token {
+		let mut yak = Yak::new();
+		let key = yak.start_session().into();
 
 		session.write(&Packet::Acknowledge { key });
 
-		self.session = Some(session);
-	}
+		let token = Token(self.next_token);
 
-	/// Tries to accept an incoming connection if not
-	/// already connected to a recipient.
-	fn try_accept(&mut self) {
-		if self.session.is_some() {
-			return;
+		self.next_token += 1;
+
+		self.peers.insert(
+			token,
+			Peer {
+				yak,
+				session,
+				label,
+				log: String::new(),
+				secure: false,
+				initiator,
+			},
+		);
+
+		if self.selected.is_none() {
+			self.selected = Some(token);
 		}
 
+		token
+	}
+
+	/// Tries to accept an incoming connection, giving it its own peer slot.
+	fn try_accept(&mut self) {
 		if let Ok((stream, address)) = self.server.accept() {
 			match Session::from_stream(stream) {
 				Ok(session) => {
 					self.log("net", format_args!("receiving from {address}"));
 
-					self.set_session(session);
+					self.add_peer(session, address.to_string(), false);
 				}
 				Err(error) => {
 					self.log("net", format_args!("{error} from {address}"));
@@ -129,65 +156,134 @@ impl Application {
 		}
 	}
 
-	/// Disconnects from the current recipient.
-	/// This will also send a [`Packet::Leave`] packet to the recipient.
-	fn disconnect(&mut self) {
-		if let Some(mut session) = self.session.take() {
-			session.write(&Packet::Leave);
+	/// Disconnects the given peer, sending it a [`Packet::Leave`] packet and
+	/// dropping it from the table.
+	fn disconnect(&mut self, token: Token) {
+		if let Some(mut peer) = self.peers.remove(&token) {
+			peer.session.write(&Packet::Leave);
+
+			// Best-effort attempt to push the farewell before dropping.
+			let _ = peer.session.flush();
+		}
+
+		if self.selected == Some(token) {
+			self.selected = self.peers.keys().next().copied();
 		}
 	}
 
-	/// Tries to connect to the recipient.
-	/// This will also send an [`Packet::Acknowledge`] packet to the recipient.
+	/// Tries to connect to the recipient, adding it as a new peer.
 	fn connect(&mut self) {
-		match Session::from_recipient(&self.recipient) {
-			Ok(session) => self.set_session(session),
+		let recipient = self.recipient.clone();
+
+		match Session::from_recipient(&recipient) {
+			Ok(session) => {
+				self.add_peer(session, recipient, true);
+			}
 			Err(error) => self.log("net", format_args!("failed to connect: {error}")),
 		}
 	}
 
-	/// Tries to send the message box to the recipient.
+	/// Joins the discovery network through the address in the recipient box.
+	fn bootstrap(&mut self) {
+		let recipient = self.recipient.clone();
+
+		match recipient.parse() {
+			Ok(addr) => {
+				self.discovery.bootstrap(addr);
+
+				self.log("dht", format_args!("bootstrapping from {recipient}"));
+			}
+			Err(error) => self.log("dht", format_args!("bad bootstrap address: {error}")),
+		}
+	}
+
+	/// Opens an encrypted session to a discovered peer's advertised address.
+	fn dial(&mut self, address: SocketAddr) {
+		match Session::from_recipient(address) {
+			Ok(session) => {
+				self.add_peer(session, address.to_string(), true);
+			}
+			Err(error) => self.log("net", format_args!("failed to connect: {error}")),
+		}
+	}
+
+	/// Sends the message box to the selected peer.
 	fn send(&mut self) {
-		if let Some(session) = &mut self.session {
-			let packet = Packet::Message {
-				data: std::mem::take(&mut self.message_box),
-			};
+		if let Some(peer) = self.selected.and_then(|token| self.peers.get_mut(&token)) {
+			let data = std::mem::take(&mut self.message_box);
+
+			peer.log = format!("[me] {data}\n") + &peer.log;
 
-			session.write(&packet);
+			peer.session.write(&Packet::Message { data });
 		}
 	}
 
-	/// Tries to process packets from the recipient.
-	/// Returns the new status of the connection.
-	fn try_process(&mut self) -> Status {
-		if let Some(mut session) = std::mem::take(&mut self.session) {
-			while let Some(data) = session.read() {
-				match data {
-					Packet::Acknowledge { key } => {
-						let key = self.yak.compute_shared(*key);
+	/// Drains every peer's outbound queue, dropping any peer whose socket
+	/// errors.
+	fn try_flush(&mut self) {
+		let mut dead = Vec::new();
 
-						session.secure(key);
+		for (&token, peer) in &mut self.peers {
+			if let Err(error) = peer.session.flush() {
+				dead.push((token, error.to_string()));
+			}
+		}
 
-						println!("KEY: {key}");
+		for (token, error) in dead {
+			self.peers.remove(&token);
+
+			self.log("net", format_args!("failed to send: {error}"));
+
+			if self.selected == Some(token) {
+				self.selected = self.peers.keys().next().copied();
+			}
+		}
+	}
 
-						self.log("net", format_args!("keys have been exchanged"));
+	/// Polls every peer for packets, completing handshakes, routing messages to
+	/// the originating peer's log, and removing peers that leave.
+	fn try_process(&mut self) {
+		let mut events = Vec::new();
+		let mut leaves = Vec::new();
+
+		for (&token, peer) in &mut self.peers {
+			while let Some(packet) = peer.session.read() {
+				match packet {
+					Packet::Acknowledge { key } => {
+						let shared = peer.yak.compute_shared(*key);
+
+						peer.session.secure(shared, peer.initiator);
+						peer.secure = true;
+
+						events.push(format!("keys exchanged with {}", peer.label));
 					}
 					Packet::Message { data } => {
-						self.log("msg", format_args!("{data}"));
+						peer.log = format!("[{}] {}\n", peer.label, data) + &peer.log;
 					}
+					// Fragments are reassembled inside the session and surface as
+					// a whole `Message`, so one should never reach here.
+					Packet::MessageFragment { .. } => {}
 					Packet::Leave => {
-						self.log("net", format_args!("the recipient has disconnected"));
+						leaves.push(token);
 
-						return Status::Inactive;
+						break;
 					}
 				}
 			}
+		}
+
+		for event in events {
+			self.log("net", format_args!("{event}"));
+		}
 
-			self.session = Some(session);
+		for token in leaves {
+			if let Some(peer) = self.peers.remove(&token) {
+				self.log("net", format_args!("{} has disconnected", peer.label));
+			}
 
-			Status::Active
-		} else {
-			Status::Inactive
+			if self.selected == Some(token) {
+				self.selected = self.peers.keys().next().copied();
+			}
 		}
 	}
 }
@@ -195,51 +291,109 @@ impl Application {
 impl eframe::App for Application {
 	fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
 		self.try_accept();
+		self.try_process();
+		self.try_flush();
+
+		self.discovery.poll();
+		self.discovery.tick();
+
+		let discovered = self.discovery.discovered();
+
+		// A stable, sorted snapshot of the peers so the list does not jitter
+		// with the hash map's iteration order.
+		let mut peers: Vec<_> = self
+			.peers
+			.iter()
+			.map(|(&token, peer)| (token, peer.label.clone(), peer.secure, peer.session.pending()))
+			.collect();
 
-		let status = self.try_process();
+		peers.sort_by_key(|(token, ..)| token.0);
+
+		let active = self
+			.selected
+			.and_then(|token| self.peers.get(&token))
+			.is_some_and(|peer| peer.secure);
+
+		let mut conversation = self
+			.selected
+			.and_then(|token| self.peers.get(&token))
+			.map(|peer| peer.log.clone())
+			.unwrap_or_default();
 
 		CentralPanel::default().show(ctx, |ui| {
-			let grid = Grid::new("Info").num_columns(3).min_col_width(50.0);
+			ui.separator();
+
+			ui.horizontal(|ui| {
+				let recipient = TextEdit::singleline(&mut self.recipient).hint_text("Address");
+
+				ui.add(recipient);
+
+				if ui.button("Connect").clicked() {
+					self.connect();
+				}
+
+				if ui.button("Bootstrap").clicked() {
+					self.bootstrap();
+				}
+			});
 
 			ui.separator();
 
-			grid.show(ui, |ui| {
-				ui.label("Status");
+			ui.label("Discovered");
 
-				let (color, label) = status.as_label();
+			for (id, address) in &discovered {
+				if ui.button(format!("{} ({address})", id.short())).clicked() {
+					self.dial(*address);
+				}
+			}
 
-				ui.colored_label(color, label);
+			if discovered.is_empty() {
+				ui.label("no nodes");
+			}
 
-				ui.end_row();
+			ui.separator();
 
-				ui.label("Recipient");
+			ui.label("Peers");
 
-				let button = Button::new(status.as_set_reset()).min_size(Vec2::new(50.0, 0.0));
+			for (token, label, secure, pending) in &peers {
+				ui.horizontal(|ui| {
+					ui.selectable_value(&mut self.selected, Some(*token), label.as_str());
 
-				if ui.add(button).clicked() {
-					if status.is_inactive() {
-						self.connect();
+					let (color, text) = if *secure {
+						(Color32::LIGHT_GREEN, "active")
 					} else {
-						self.disconnect();
+						(Color32::LIGHT_RED, "handshaking")
+					};
+
+					ui.colored_label(color, text);
+
+					if *pending > 0 {
+						ui.colored_label(Color32::LIGHT_YELLOW, format!("sending... ({pending})"));
 					}
-				}
+				});
+			}
 
-				let recipient = TextEdit::singleline(&mut self.recipient).hint_text("Recipient");
+			if peers.is_empty() {
+				ui.label("no peers");
+			}
 
-				ui.add_enabled(status.is_inactive(), recipient)
-			});
+			if let Some(token) = self.selected {
+				if ui.button("Disconnect").clicked() {
+					self.disconnect(token);
+				}
+			}
 
 			ui.separator();
 
 			ui.vertical_centered_justified(|ui| {
-				let button = Button::new("Send").min_size(Vec2::new(50.0, 0.0));
 				let data = TextEdit::multiline(&mut self.message_box).hint_text("Message");
 
 				ui.add(data);
 
-				let active = status.is_active() && !self.message_box.is_empty();
+				let button = Button::new("Send").min_size(Vec2::new(50.0, 0.0));
+				let enabled = active && !self.message_box.is_empty();
 
-				if ui.add_enabled(active, button).clicked() {
+				if ui.add_enabled(enabled, button).clicked() {
 					self.send();
 				}
 			});
@@ -247,11 +401,17 @@ impl eframe::App for Application {
 			ui.separator();
 
 			ui.vertical_centered_justified(|ui| {
-				let text = TextEdit::multiline(&mut self.output_label)
+				let conversation = TextEdit::multiline(&mut conversation)
+					.desired_rows(0)
+					.hint_text("Conversation");
+
+				ui.add_enabled(false, conversation);
+
+				let network = TextEdit::multiline(&mut self.output_label)
 					.desired_rows(0)
-					.hint_text("Output");
+					.hint_text("Network");
 
-				ui.add_enabled(false, text);
+				ui.add_enabled(false, network)
 			})
 		});
 	}
@@ -269,11 +429,27 @@ fn load_server(port: u16) -> TcpListener {
 	server
 }
 
+/// Loads the discovery subsystem, binding a UDP socket on the same port as the
+/// TCP listener and advertising the TCP address as the session endpoint.
+///
+/// The long term identity key is kept by the returned [`Discovery`] for its
+/// whole lifetime, since it has to keep signing discovery packets with the
+/// same key its advertised `NodeId` was derived from.
+fn load_discovery(port: u16) -> Discovery {
+	let socket = SocketAddr::from(([127, 0, 0, 1], port));
+	let udp = UdpSocket::bind(socket).expect("could not bind to socket");
+
+	Discovery::new(udp, Yak::new(), socket).expect("could not start discovery")
+}
+
 /// The entry point.
 /// It parses the command line arguments and starts the application.
 fn main() -> Result<(), eframe::Error> {
 	let argument = std::env::args().nth(1).expect("please specify a port");
-	let server = load_server(argument.parse().expect("could not parse port"));
+	let port = argument.parse().expect("could not parse port");
+
+	let server = load_server(port);
+	let discovery = load_discovery(port);
 
 	let options = NativeOptions {
 		initial_window_size: Some(Vec2::new(400.0, 500.0)),
@@ -284,6 +460,6 @@ fn main() -> Result<(), eframe::Error> {
 	eframe::run_native(
 		"Secure Sender",
 		options,
-		Box::new(move |_cc| Box::new(Application::new(server))),
+		Box::new(move |_cc| Box::new(Application::new(server, discovery))),
 	)
 }
@@ -1,10 +1,52 @@
 use std::{
-	io::{Read, Write},
+	collections::{HashMap, VecDeque},
+	io::{Cursor, ErrorKind, Read, Write},
 	net::{TcpStream, ToSocketAddrs},
+	time::{Duration, Instant},
 };
 
+use tiny_keccak::{Hasher, Keccak};
+
 use crate::{rc4::Rc4, yak::U1024};
 
+/// The number of bytes in an integrity tag appended to every frame.
+const TAG_SIZE: usize = 16;
+
+/// Domain-separation label for the MAC chain covering frames sent by the
+/// peer that initiated the TCP connection.
+const MAC_LABEL_INITIATOR: &[u8] = b"secure-chat-mac-initiator";
+
+/// Domain-separation label for the MAC chain covering frames sent by the
+/// peer that accepted the TCP connection.
+const MAC_LABEL_RESPONDER: &[u8] = b"secure-chat-mac-responder";
+
+/// The size of the fixed frame header: a discriminant byte followed by the
+/// little-endian payload length.
+const HEADER_SIZE: usize = 1 + std::mem::size_of::<usize>();
+
+/// The largest payload a single frame may declare. Oversized headers are
+/// rejected before anything is allocated so a peer cannot force a huge
+/// allocation with a forged length.
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Messages larger than this are split into ordered fragments before sending.
+const MTU: usize = 16 * 1024;
+
+/// The most fragments an inbound message may be reassembled from; sets that
+/// declare more are dropped. Outgoing messages are split without this cap.
+const MAX_FRAGMENTS: usize = 4096;
+
+/// The number of partial messages that may be buffered for reassembly at once.
+const MAX_REASSEMBLY: usize = 16;
+
+/// The combined size of all buffered reassembly fragments, bounding memory
+/// against a peer that sends many partial messages.
+const MAX_REASSEMBLY_SIZE: usize = MAX_PAYLOAD_SIZE;
+
+/// How long a partial message may wait for its missing fragments before it is
+/// discarded.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Dumps the key to a byte array.
 fn key_to_bytes(key: U1024) -> [u8; 128] {
 	let mut data = [0; 128];
@@ -14,6 +56,53 @@ fn key_to_bytes(key: U1024) -> [u8; 128] {
 	data
 }
 
+/// Derives a running MAC state from the session key and a direction label.
+///
+/// A dedicated MAC key is hashed out of the session key with a
+/// domain-separation prefix so the integrity tags never reuse the RC4 key
+/// material directly. The prefix also encodes which direction the chain
+/// covers, so one peer's out-chain and its own in-chain start from different
+/// states; only the out-chain label on one side matches the in-chain label
+/// on the other, which is what actually needs to agree.
+fn mac_state(key: &[u8], label: &[u8]) -> Keccak {
+	let mut derive = Keccak::v256();
+
+	derive.update(label);
+	derive.update(key);
+
+	let mut secret = [0; 32];
+
+	derive.finalize(&mut secret);
+
+	let mut mac = Keccak::v256();
+
+	mac.update(&secret);
+
+	mac
+}
+
+/// Finalizes a copy of the running MAC state into a tag.
+/// Cloning keeps the underlying chain intact so it can keep absorbing.
+fn mac_tag(mac: &Keccak) -> [u8; TAG_SIZE] {
+	let mut digest = [0; 32];
+
+	mac.clone().finalize(&mut digest);
+
+	digest[..TAG_SIZE].try_into().unwrap()
+}
+
+/// Compares two tags in constant time to avoid leaking information through
+/// timing when rejecting a tampered frame.
+fn tags_match(a: &[u8; TAG_SIZE], b: &[u8; TAG_SIZE]) -> bool {
+	let mut diff = 0;
+
+	for (x, y) in a.iter().zip(b.iter()) {
+		diff |= x ^ y;
+	}
+
+	diff == 0
+}
+
 /// A packet that can be sent over the network.
 pub enum Packet {
 	/// The initial packet that sets up the session key.
@@ -22,6 +111,14 @@ pub enum Packet {
 	/// A message that is sent to the recipient.
 	Message { data: String },
 
+	/// One ordered fragment of a message too large to send in a single frame.
+	MessageFragment {
+		id: u32,
+		index: u32,
+		count: u32,
+		data: Vec<u8>,
+	},
+
 	/// A packet that indicates that the sender is leaving.
 	Leave,
 }
@@ -32,65 +129,108 @@ impl Packet {
 		match self {
 			Self::Acknowledge { .. } => 0,
 			Self::Message { .. } => 1,
-			Self::Leave => 2,
+			Self::MessageFragment { .. } => 2,
+			Self::Leave => 3,
 		}
 	}
 
-	/// Serializes the packet to the writer as a byte array.
-	fn serialize(&self, writer: &mut dyn Write) {
-		writer.write_all(&[self.discriminant()]).unwrap();
-
+	/// Returns the variable-length body carried by the packet.
+	fn payload(&self) -> Vec<u8> {
 		match self {
-			Self::Acknowledge { key } => {
-				let bytes = key_to_bytes(**key);
-
-				writer.write_all(&bytes).unwrap();
-			}
-			Self::Message { data } => {
-				let len = data.len();
-
-				writer.write_all(&len.to_le_bytes()).unwrap();
-				writer.write_all(data.as_bytes()).unwrap();
+			Self::Acknowledge { key } => key_to_bytes(**key).to_vec(),
+			Self::Message { data } => data.as_bytes().to_vec(),
+			Self::MessageFragment {
+				id,
+				index,
+				count,
+				data,
+			} => {
+				let mut payload = Vec::with_capacity(12 + data.len());
+
+				payload.extend_from_slice(&id.to_le_bytes());
+				payload.extend_from_slice(&index.to_le_bytes());
+				payload.extend_from_slice(&count.to_le_bytes());
+				payload.extend_from_slice(data);
+
+				payload
 			}
-			Self::Leave => {}
+			Self::Leave => Vec::new(),
 		}
 	}
 
-	/// Tries to deserialize a packet from the reader.
-	fn try_deserialize(reader: &mut dyn Read) -> Option<(Self, usize)> {
-		const LEN: usize = std::mem::size_of::<usize>();
-
-		let mut buffer = [0; 128];
+	/// Serializes the packet to the writer as a fixed header (discriminant plus
+	/// payload length) followed by the payload bytes.
+	fn serialize(&self, writer: &mut dyn Write) {
+		let payload = self.payload();
 
-		reader.read_exact(&mut buffer[..1]).ok()?;
+		writer.write_all(&[self.discriminant()]).unwrap();
+		writer.write_all(&payload.len().to_le_bytes()).unwrap();
+		writer.write_all(&payload).unwrap();
+	}
 
-		let data = match buffer[0] {
+	/// Reconstructs a packet from its decoded discriminant and payload bytes.
+	fn from_parts(discriminant: u8, payload: &[u8]) -> Option<Self> {
+		let packet = match discriminant {
 			0 => {
-				reader.read_exact(&mut buffer).ok()?;
-
-				let key = U1024::from_little_endian(&buffer).into();
+				let bytes: [u8; 128] = payload.try_into().ok()?;
 
-				(Self::Acknowledge { key }, 128)
+				Self::Acknowledge {
+					key: U1024::from_little_endian(&bytes).into(),
+				}
 			}
-			1 => {
-				reader.read_exact(&mut buffer[..LEN]).ok()?;
+			1 => Self::Message {
+				data: String::from_utf8(payload.to_vec()).ok()?,
+			},
+			2 => {
+				let header: [u8; 12] = payload.get(..12)?.try_into().ok()?;
+
+				Self::MessageFragment {
+					id: u32::from_le_bytes(header[..4].try_into().unwrap()),
+					index: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+					count: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+					data: payload[12..].to_vec(),
+				}
+			}
+			3 => Self::Leave,
+			_ => return None,
+		};
 
-				let len = usize::from_le_bytes(buffer[..LEN].try_into().unwrap());
+		Some(packet)
+	}
+}
 
-				let mut data = vec![0; len];
+/// The result of draining the outbound send queue.
+pub enum WriteStatus {
+	/// The socket could not accept the whole queue; bytes remain buffered.
+	Ongoing,
 
-				reader.read_exact(&mut data).ok()?;
+	/// The queue was fully flushed to the socket.
+	Complete,
+}
 
-				let data = String::from_utf8(data).ok()?;
+/// A partially received message being reassembled from fragments.
+struct Reassembly {
+	/// The fragments received so far, indexed by `fragment_index`.
+	fragments: Vec<Option<Vec<u8>>>,
 
-				(Self::Message { data }, LEN + len)
-			}
-			2 => (Self::Leave, 0),
-			_ => return None,
-		};
+	/// How many distinct fragments have arrived.
+	have: u32,
 
-		Some(data)
-	}
+	/// The combined size of the fragments buffered so far.
+	size: usize,
+
+	/// When the first fragment arrived, used to time the set out.
+	started: Instant,
+}
+
+/// Tracks where the read side is within the current inbound frame.
+enum ReadState {
+	/// Waiting for the fixed-size header describing the next frame.
+	ReadingHeader,
+
+	/// Waiting for the `payload_len` body bytes (and trailing tag) of a frame
+	/// whose header has already been decoded.
+	ReadingBody { discriminant: u8, payload_len: usize },
 }
 
 /// A session that can be used to send and receive packets.
@@ -107,8 +247,32 @@ pub struct Session {
 	/// The RC4 cipher used to decrypt incoming packets.
 	rc4_in: Rc4,
 
+	/// The running MAC chained over every outgoing frame's ciphertext.
+	mac_out: Keccak,
+
+	/// The running MAC chained over every incoming frame's ciphertext.
+	mac_in: Keccak,
+
 	/// The buffer used to store the packet data.
 	buffer: Vec<u8>,
+
+	/// Encrypted frames waiting to be written to the socket. Each cursor tracks
+	/// how much of its frame has already been accepted by the peer.
+	send_queue: VecDeque<Cursor<Vec<u8>>>,
+
+	/// Where the read side currently is within the inbound frame.
+	state: ReadState,
+
+	/// How many buffered bytes the current read state is waiting for.
+	rec_size: usize,
+
+	/// The next fragment identifier handed out by the write side.
+	fragment_seq: u32,
+
+	/// Partial inbound messages awaiting their remaining fragments, keyed by
+	/// `fragment_id`. Keeping this per-session naturally isolates one peer's
+	/// fragments from another's.
+	reassembly: HashMap<u32, Reassembly>,
 }
 
 impl Session {
@@ -121,7 +285,14 @@ impl Session {
 			stream,
 			rc4_out: Rc4::new(),
 			rc4_in: Rc4::new(),
+			mac_out: Keccak::v256(),
+			mac_in: Keccak::v256(),
 			buffer: Vec::new(),
+			send_queue: VecDeque::new(),
+			state: ReadState::ReadingHeader,
+			rec_size: HEADER_SIZE,
+			fragment_seq: 0,
+			reassembly: HashMap::new(),
 		})
 	}
 
@@ -130,45 +301,513 @@ impl Session {
 		TcpStream::connect(socket).and_then(Self::from_stream)
 	}
 
-	/// Reads a packet from the stream, if any.
-	pub fn read(&mut self) -> Option<Packet> {
-		let last = self.buffer.len();
-		let _result = self.stream.read_to_end(&mut self.buffer);
+	/// Sets how many buffered bytes the next read step is waiting for.
+	fn expect(&mut self, size: usize) {
+		self.rec_size = size;
+	}
+
+	/// Reads from the socket until the current state's `rec_size` bytes are
+	/// buffered, returning `false` when the socket has no more data right now.
+	///
+	/// Reads never ask for more than `rec_size` bytes, so a peer streaming an
+	/// endless, never-completing frame cannot grow the buffer without bound.
+	fn fill(&mut self) -> bool {
+		while self.buffer.len() < self.rec_size {
+			let needed = (self.rec_size - self.buffer.len()).min(4096);
+			let mut chunk = [0; 4096];
+
+			match self.stream.read(&mut chunk[..needed]) {
+				Ok(0) => return false,
+				Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+				Err(_) => return false,
+			}
+		}
 
-		if self.buffer.is_empty() {
+		true
+	}
+
+	/// Advances the read state machine, pulling exactly the bytes each state
+	/// needs off the socket and returning a packet once a whole frame has been
+	/// decrypted and verified.
+	///
+	/// Only full regions are ever decrypted, so the RC4 stream never advances
+	/// over a frame split across TCP segments.
+	fn readable(&mut self) -> Option<Packet> {
+		loop {
+			if !self.fill() {
+				return None;
+			}
+
+			match self.state {
+				ReadState::ReadingHeader => {
+					self.mac_in.update(&self.buffer[..HEADER_SIZE]);
+					self.rc4_in.process(&mut self.buffer[..HEADER_SIZE]);
+
+					let discriminant = self.buffer[0];
+					let payload_len =
+						usize::from_le_bytes(self.buffer[1..HEADER_SIZE].try_into().unwrap());
+
+					self.buffer.drain(..HEADER_SIZE);
+
+					if payload_len > MAX_PAYLOAD_SIZE {
+						self.buffer.clear();
+
+						return Some(Packet::Leave);
+					}
+
+					self.state = ReadState::ReadingBody {
+						discriminant,
+						payload_len,
+					};
+					self.expect(payload_len + TAG_SIZE);
+				}
+				ReadState::ReadingBody {
+					discriminant,
+					payload_len,
+				} => {
+					self.mac_in.update(&self.buffer[..payload_len]);
+					self.rc4_in.process(&mut self.buffer[..payload_len]);
+
+					let tag_end = payload_len + TAG_SIZE;
+					let received: [u8; TAG_SIZE] =
+						self.buffer[payload_len..tag_end].try_into().unwrap();
+					let expected = mac_tag(&self.mac_in);
+
+					// Advance the chain by the emitted tag so each frame's
+					// validity depends on the entire prior stream, catching
+					// reordering as well as bit-flips.
+					self.mac_in.update(&received);
+
+					self.state = ReadState::ReadingHeader;
+					self.expect(HEADER_SIZE);
+
+					if !tags_match(&expected, &received) {
+						self.buffer.clear();
+
+						return Some(Packet::Leave);
+					}
+
+					let packet = Packet::from_parts(discriminant, &self.buffer[..payload_len]);
+
+					self.buffer.drain(..tag_end);
+
+					match packet {
+						// Fragments are buffered until the whole message arrives;
+						// incomplete sets simply move on to the next frame.
+						Some(Packet::MessageFragment {
+							id,
+							index,
+							count,
+							data,
+						}) => {
+							if let Some(data) = self.reassemble(id, index, count, data) {
+								return Some(Packet::Message { data });
+							}
+						}
+						// A departing peer can never complete its partial sends.
+						Some(Packet::Leave) => {
+							self.reassembly.clear();
+
+							return Some(Packet::Leave);
+						}
+						other => return other,
+					}
+				}
+			}
+		}
+	}
+
+	/// Buffers one fragment of a message and, once every part has arrived,
+	/// returns the reassembled string. Malformed, over-capacity, or duplicate
+	/// fragments are dropped.
+	fn reassemble(&mut self, id: u32, index: u32, count: u32, data: Vec<u8>) -> Option<String> {
+		if count == 0 || count as usize > MAX_FRAGMENTS || index >= count {
 			return None;
 		}
 
-		println!("IN: {}", String::from_utf8_lossy(&self.buffer[last..]));
+		let now = Instant::now();
+
+		if !self.reassembly.contains_key(&id) {
+			// Bound the number of concurrent partial messages.
+			if self.reassembly.len() >= MAX_REASSEMBLY {
+				return None;
+			}
 
-		self.rc4_in.process(&mut self.buffer[last..]);
+			self.reassembly.insert(
+				id,
+				Reassembly {
+					fragments: vec![None; count as usize],
+					have: 0,
+					size: 0,
+					started: now,
+				},
+			);
+		}
 
-		let (packet, size) = Packet::try_deserialize(&mut self.buffer.as_slice())?;
+		let buffered: usize = self.reassembly.values().map(|entry| entry.size).sum();
+		let entry = self.reassembly.get_mut(&id)?;
 
-		self.buffer.drain(..=size);
+		// A mismatched count means the fragments cannot belong together.
+		if entry.fragments.len() != count as usize {
+			self.reassembly.remove(&id);
 
-		Some(packet)
+			return None;
+		}
+
+		if entry.fragments[index as usize].is_none() {
+			// Bound the total memory held across all partial messages.
+			if buffered + data.len() > MAX_REASSEMBLY_SIZE {
+				return None;
+			}
+
+			entry.size += data.len();
+			entry.have += 1;
+			entry.fragments[index as usize] = Some(data);
+		}
+
+		if entry.have != count {
+			return None;
+		}
+
+		let entry = self.reassembly.remove(&id)?;
+		let mut message = Vec::with_capacity(entry.size);
+
+		for fragment in entry.fragments {
+			message.extend(fragment?);
+		}
+
+		String::from_utf8(message).ok()
+	}
+
+	/// Discards partial messages that have waited too long for their missing
+	/// fragments.
+	fn purge_stale(&mut self) {
+		let now = Instant::now();
+
+		self.reassembly
+			.retain(|_, entry| now.duration_since(entry.started) <= REASSEMBLY_TIMEOUT);
 	}
 
-	/// Writes a packet to the stream.
+	/// Reads a packet from the stream, if any.
+	pub fn read(&mut self) -> Option<Packet> {
+		self.purge_stale();
+
+		self.readable()
+	}
+
+	/// Encrypts a packet and queues it for transmission.
+	///
+	/// A [`Packet::Message`] larger than [`MTU`] is split into ordered
+	/// [`Packet::MessageFragment`]s so a large paste becomes a series of bounded
+	/// frames rather than one giant write. Nothing is sent to the socket here;
+	/// the frames are drained by [`Session::flush`] so a full buffer or a
+	/// `WouldBlock` can never panic the caller.
 	pub fn write(&mut self, data: &Packet) {
-		let last = self.buffer.len();
+		match data {
+			Packet::Message { data } if data.len() > MTU => self.write_fragments(data.as_bytes()),
+			_ => self.enqueue(data),
+		}
+	}
 
-		data.serialize(&mut self.buffer);
+	/// Splits an oversized message body into fragments and queues each one.
+	fn write_fragments(&mut self, data: &[u8]) {
+		let id = self.fragment_seq;
 
-		self.rc4_out.process(&mut self.buffer[last..]);
+		self.fragment_seq = self.fragment_seq.wrapping_add(1);
 
-		println!("OUT: {}", String::from_utf8_lossy(&self.buffer[last..]));
+		let chunks = data.chunks(MTU);
+		let count = chunks.len() as u32;
 
-		self.stream.write_all(&self.buffer[last..]).unwrap();
-		self.buffer.drain(last..);
+		for (index, chunk) in chunks.enumerate() {
+			self.enqueue(&Packet::MessageFragment {
+				id,
+				index: index as u32,
+				count,
+				data: chunk.to_vec(),
+			});
+		}
 	}
 
-	/// Sets the session key and initializes the RC4 ciphers.
-	pub fn secure(&mut self, key: U1024) {
+	/// Encrypts a single packet into a frame and appends it to the send queue.
+	fn enqueue(&mut self, data: &Packet) {
+		let mut frame = Vec::new();
+
+		data.serialize(&mut frame);
+
+		self.rc4_out.process(&mut frame);
+
+		// Authenticate the ciphertext with the running MAC, append the tag, and
+		// absorb the tag so the egress chain stays stateful.
+		self.mac_out.update(&frame);
+
+		let tag = mac_tag(&self.mac_out);
+
+		self.mac_out.update(&tag);
+		frame.extend_from_slice(&tag);
+
+		self.send_queue.push_back(Cursor::new(frame));
+	}
+
+	/// Drains the outbound send queue to the socket, advancing each frame's
+	/// cursor by the number of bytes the peer actually accepted and stopping on
+	/// `WouldBlock` so back-pressure is handled instead of panicking.
+	pub fn flush(&mut self) -> std::io::Result<WriteStatus> {
+		while let Some(cursor) = self.send_queue.front_mut() {
+			let position = cursor.position() as usize;
+			let frame = cursor.get_ref();
+
+			match self.stream.write(&frame[position..]) {
+				Ok(0) => return Ok(WriteStatus::Ongoing),
+				Ok(written) => {
+					let position = position + written;
+
+					cursor.set_position(position as u64);
+
+					if position == cursor.get_ref().len() {
+						self.send_queue.pop_front();
+					}
+				}
+				Err(error) if error.kind() == ErrorKind::WouldBlock => {
+					return Ok(WriteStatus::Ongoing);
+				}
+				Err(error) => return Err(error),
+			}
+		}
+
+		Ok(WriteStatus::Complete)
+	}
+
+	/// Returns the number of frames still waiting to be written, so the UI can
+	/// surface a "sending..." indicator.
+	pub fn pending(&self) -> usize {
+		self.send_queue.len()
+	}
+
+	/// Sets the session key and initializes the RC4 ciphers and running MACs.
+	///
+	/// `initiator` must be `true` on the peer that opened the TCP connection
+	/// and `false` on the peer that accepted it, so the two MAC chains get
+	/// distinct labels on each side: a peer's own out-chain and in-chain never
+	/// start from the same state, while its out-chain still matches the
+	/// other peer's in-chain (and vice versa).
+	pub fn secure(&mut self, key: U1024, initiator: bool) {
 		let bytes = key_to_bytes(key);
 
 		self.rc4_out.initialize(&bytes);
 		self.rc4_in.initialize(&bytes);
+
+		let (out_label, in_label) = if initiator {
+			(MAC_LABEL_INITIATOR, MAC_LABEL_RESPONDER)
+		} else {
+			(MAC_LABEL_RESPONDER, MAC_LABEL_INITIATOR)
+		};
+
+		self.mac_out = mac_state(&bytes, out_label);
+		self.mac_in = mac_state(&bytes, in_label);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::TcpListener;
+
+	use super::*;
+
+	/// A fixed session key shared by both ends of the test connections.
+	fn key() -> U1024 {
+		U1024::from(0x0123_4567_89ab_cdef_u64)
+	}
+
+	/// Creates a session connected to a raw (unencrypted) peer socket: writing
+	/// to the returned stream feeds the session, reading from it yields whatever
+	/// the session wrote.
+	fn session_with_raw_peer() -> (Session, TcpStream) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (server, _) = listener.accept().unwrap();
+
+		(Session::from_stream(client).unwrap(), server)
+	}
+
+	/// Creates a pair of sessions secured with the same key, talking over a
+	/// loopback connection.
+	fn secured_pair() -> (Session, Session) {
+		let (mut a, raw) = session_with_raw_peer();
+		let mut b = Session::from_stream(raw).unwrap();
+
+		a.secure(key(), true);
+		b.secure(key(), false);
+
+		(a, b)
+	}
+
+	/// Drains all of a peer socket's currently available bytes.
+	fn read_frame(peer: &mut TcpStream) -> Vec<u8> {
+		peer.set_nonblocking(true).unwrap();
+
+		let mut out = Vec::new();
+		let mut idle = 0;
+
+		while idle < 50 {
+			let mut chunk = [0; 4096];
+
+			match peer.read(&mut chunk) {
+				Ok(0) => break,
+				Ok(read) => {
+					out.extend_from_slice(&chunk[..read]);
+					idle = 0;
+				}
+				Err(ref error) if error.kind() == ErrorKind::WouldBlock => {
+					if !out.is_empty() {
+						idle += 1;
+					}
+
+					std::thread::sleep(Duration::from_millis(1));
+				}
+				Err(_) => break,
+			}
+		}
+
+		out
+	}
+
+	/// Serializes and encrypts a packet into its on-the-wire frame.
+	fn encode_frame(packet: &Packet) -> Vec<u8> {
+		let (mut session, mut peer) = session_with_raw_peer();
+
+		session.secure(key(), true);
+		session.write(packet);
+
+		for _ in 0..100 {
+			if matches!(session.flush(), Ok(WriteStatus::Complete)) {
+				break;
+			}
+		}
+
+		read_frame(&mut peer)
+	}
+
+	/// Reads from a session until a packet arrives.
+	fn pump_read(session: &mut Session) -> Packet {
+		for _ in 0..2000 {
+			if let Some(packet) = session.read() {
+				return packet;
+			}
+
+			std::thread::sleep(Duration::from_millis(1));
+		}
+
+		panic!("no packet received");
+	}
+
+	/// Sends a packet through one session and reads it from the other.
+	fn round_trip(tx: &mut Session, rx: &mut Session, packet: &Packet) -> Packet {
+		tx.write(packet);
+
+		for _ in 0..2000 {
+			let _ = tx.flush();
+
+			if let Some(packet) = rx.read() {
+				return packet;
+			}
+
+			std::thread::sleep(Duration::from_millis(1));
+		}
+
+		panic!("no packet received");
+	}
+
+	#[test]
+	fn mac_round_trip_preserves_messages() {
+		let (mut a, mut b) = secured_pair();
+
+		let packet = Packet::Message {
+			data: "hello world".to_owned(),
+		};
+
+		match round_trip(&mut a, &mut b, &packet) {
+			Packet::Message { data } => assert_eq!(data, "hello world"),
+			_ => panic!("unexpected packet"),
+		}
+	}
+
+	#[test]
+	fn tampered_body_is_rejected() {
+		let mut frame = encode_frame(&Packet::Message {
+			data: "tamper me".to_owned(),
+		});
+
+		// Flip a byte in the encrypted body; the running MAC must notice.
+		frame[HEADER_SIZE] ^= 0x01;
+
+		let (mut rx, mut peer) = session_with_raw_peer();
+
+		rx.secure(key(), false);
+		peer.write_all(&frame).unwrap();
+
+		assert!(matches!(pump_read(&mut rx), Packet::Leave));
+	}
+
+	#[test]
+	fn frame_split_across_segments_reassembles() {
+		let frame = encode_frame(&Packet::Message {
+			data: "chunked across segments".to_owned(),
+		});
+
+		let (mut rx, mut peer) = session_with_raw_peer();
+
+		rx.secure(key(), false);
+
+		// Deliver the frame in two TCP segments; a partial frame must not yield
+		// a packet or desync the RC4 stream.
+		let middle = frame.len() / 2;
+
+		peer.write_all(&frame[..middle]).unwrap();
+
+		assert!(rx.read().is_none());
+
+		peer.write_all(&frame[middle..]).unwrap();
+
+		match pump_read(&mut rx) {
+			Packet::Message { data } => assert_eq!(data, "chunked across segments"),
+			_ => panic!("unexpected packet"),
+		}
+	}
+
+	#[test]
+	fn fragments_reassemble_out_of_order() {
+		let (mut session, _peer) = session_with_raw_peer();
+
+		assert!(session.reassemble(7, 1, 2, b"world".to_vec()).is_none());
+
+		assert_eq!(
+			session.reassemble(7, 0, 2, b"hello ".to_vec()).as_deref(),
+			Some("hello world"),
+		);
+	}
+
+	#[test]
+	fn duplicate_fragments_are_ignored() {
+		let (mut session, _peer) = session_with_raw_peer();
+
+		assert!(session.reassemble(1, 0, 2, b"ab".to_vec()).is_none());
+
+		// A second copy of index 0 must not overwrite the first or count twice.
+		assert!(session.reassemble(1, 0, 2, b"XX".to_vec()).is_none());
+
+		assert_eq!(
+			session.reassemble(1, 1, 2, b"cd".to_vec()).as_deref(),
+			Some("abcd"),
+		);
+	}
+
+	#[test]
+	fn mismatched_fragment_count_is_dropped() {
+		let (mut session, _peer) = session_with_raw_peer();
+
+		assert!(session.reassemble(3, 0, 2, b"ab".to_vec()).is_none());
+
+		// A fragment claiming a different count cannot belong to the same set.
+		assert!(session.reassemble(3, 0, 3, b"ab".to_vec()).is_none());
 	}
 }
@@ -1,4 +1,5 @@
 use rand::{rngs::ThreadRng, RngCore};
+use tiny_keccak::{Hasher, Keccak};
 
 uint::construct_uint! {
 	/// A 1024 bit unsigned integer.
@@ -34,6 +35,45 @@ fn fixed_exponentiation(base: U1024, exponent: U1024) -> U1024 {
 	modular_exponentiation(base, exponent, LARGE_SHARED_PRIME.with(Clone::clone))
 }
 
+/// `p - 1`. Reducing an exponent by this before combining it with others
+/// never changes `g^exponent mod p`, since `g^(p - 1) = 1 (mod p)` by
+/// Fermat's little theorem (`p` is prime). That keeps the [`Signature`] math
+/// below comfortably inside a single [`U1024`] instead of overflowing it.
+fn group_order() -> U1024 {
+	LARGE_SHARED_PRIME.with(Clone::clone) - U1024::one()
+}
+
+/// Adds two values modulo `modulus` without overflowing, by reducing each
+/// operand first.
+fn add_mod(a: U1024, b: U1024, modulus: U1024) -> U1024 {
+	(a % modulus).overflowing_add(b % modulus).0 % modulus
+}
+
+/// Multiplies two values modulo `modulus` without overflowing, by reducing
+/// each operand first.
+fn mul_mod(a: U1024, b: U1024, modulus: U1024) -> U1024 {
+	(a % modulus).overflowing_mul(b % modulus).0 % modulus
+}
+
+/// Hashes a signature's commitment together with the signed message into the
+/// Fiat-Shamir challenge, reduced into the exponent group.
+fn challenge(commitment: U1024, message: &[u8]) -> U1024 {
+	let mut commitment_bytes = [0; 128];
+
+	commitment.to_little_endian(&mut commitment_bytes);
+
+	let mut hasher = Keccak::v256();
+
+	hasher.update(&commitment_bytes);
+	hasher.update(message);
+
+	let mut digest = [0; 32];
+
+	hasher.finalize(&mut digest);
+
+	U1024::from_little_endian(&digest) % group_order()
+}
+
 /// Pulls a random 1024 bit number from the RNG.
 fn random_u1024(rng: &mut ThreadRng) -> U1024 {
 	let mut data = [0; 128];
@@ -76,6 +116,13 @@ impl Yak {
 		}
 	}
 
+	/// Returns the long term public key, `2^key mod p`.
+	/// Unlike the per-session key this is stable across sessions, which makes it
+	/// suitable for deriving a persistent node identity.
+	pub fn public_key(&self) -> U1024 {
+		fixed_exponentiation(U1024::from(2), self.key)
+	}
+
 	/// Starts a new session and returns the public key.
 	pub fn start_session(&mut self) -> U1024 {
 		self.session = random_field_u1024(&mut self.rng);
@@ -88,4 +135,109 @@ impl Yak {
 	pub fn compute_shared(&self, key: U1024) -> U1024 {
 		fixed_exponentiation(key, self.key + self.session)
 	}
+
+	/// Signs `message` with the long term key, proving whoever verifies it
+	/// that it was produced by the holder of [`Yak::public_key`] without
+	/// revealing the key itself.
+	///
+	/// This is a Schnorr-style signature over the same group used for the
+	/// key exchange: a fresh one-time commitment is hashed together with the
+	/// message into a challenge, which is folded into the response.
+	pub fn sign(&mut self, message: &[u8]) -> Signature {
+		let order = group_order();
+		let nonce = random_field_u1024(&mut self.rng);
+		let commitment = fixed_exponentiation(U1024::from(2), nonce);
+		let e = challenge(commitment, message);
+		let response = add_mod(nonce, mul_mod(e, self.key % order, order), order);
+
+		Signature { commitment, response }
+	}
+}
+
+/// A Schnorr-style signature proving knowledge of the private key behind a
+/// [`Yak::public_key`].
+#[derive(Clone, Copy)]
+pub struct Signature {
+	/// The prover's one-time commitment, `2^nonce mod p`.
+	commitment: U1024,
+
+	/// The response binding the commitment to the private key and the
+	/// challenge derived from the signed message.
+	response: U1024,
+}
+
+impl Signature {
+	/// The size of a signature's wire format: a commitment and a response,
+	/// each a 128 byte big number.
+	pub const SIZE: usize = 256;
+
+	/// Verifies the signature over `message` against the claimed
+	/// `public_key`.
+	pub fn verify(&self, message: &[u8], public_key: U1024) -> bool {
+		let e = challenge(self.commitment, message);
+		let prime = LARGE_SHARED_PRIME.with(Clone::clone);
+
+		let lhs = fixed_exponentiation(U1024::from(2), self.response);
+		let rhs = mul_mod(self.commitment, modular_exponentiation(public_key, e, prime), prime);
+
+		lhs == rhs
+	}
+
+	/// Serializes the signature to its wire format: the commitment followed
+	/// by the response, each little-endian.
+	pub fn to_bytes(self) -> [u8; Self::SIZE] {
+		let mut bytes = [0; Self::SIZE];
+
+		self.commitment.to_little_endian(&mut bytes[..128]);
+		self.response.to_little_endian(&mut bytes[128..]);
+
+		bytes
+	}
+
+	/// Parses a signature from its wire format.
+	pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+		Self {
+			commitment: U1024::from_little_endian(&bytes[..128]),
+			response: U1024::from_little_endian(&bytes[128..]),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn signature_round_trip_verifies() {
+		let mut yak = Yak::new();
+		let signature = yak.sign(b"hello");
+
+		assert!(signature.verify(b"hello", yak.public_key()));
+	}
+
+	#[test]
+	fn signature_rejects_tampered_message() {
+		let mut yak = Yak::new();
+		let signature = yak.sign(b"hello");
+
+		assert!(!signature.verify(b"goodbye", yak.public_key()));
+	}
+
+	#[test]
+	fn signature_rejects_wrong_public_key() {
+		let mut signer = Yak::new();
+		let impostor = Yak::new();
+		let signature = signer.sign(b"hello");
+
+		assert!(!signature.verify(b"hello", impostor.public_key()));
+	}
+
+	#[test]
+	fn signature_bytes_round_trip() {
+		let mut yak = Yak::new();
+		let signature = yak.sign(b"hello");
+		let decoded = Signature::from_bytes(&signature.to_bytes());
+
+		assert!(decoded.verify(b"hello", yak.public_key()));
+	}
 }